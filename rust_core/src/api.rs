@@ -5,14 +5,25 @@ use std::future::Future;
 use std::os::raw::c_char;
 use std::path::PathBuf;
 use std::ptr;
+use std::sync::mpsc;
 use std::thread;
+use std::time::Duration;
 
 use tokio::runtime::{Builder, Runtime};
+use tokio::sync::watch;
 
-use crate::commit_manager::{self, enqueue_commit, queue_len};
+use crate::commit_manager::{
+    self, dead_queue_len, drain_dead_letter_queue, enqueue_commit, queue_len, BatchConfig,
+    RetryConfig, UploadMode,
+};
 use crate::errors::{AppError, FfiError};
+use crate::events::{self, EventCallback};
 use crate::server::{Commit, NeonAPI};
 
+/// How long [`sparkwms_stop_commit_manager`] waits for the background thread to join before
+/// giving up and returning control to the caller anyway.
+const SHUTDOWN_JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
 const DEFAULT_QUEUE_PATH: &str = "commit_queue.json";
 
 /// Handle that keeps the API client and the Tokio runtime alive for FFI callers.
@@ -226,6 +237,37 @@ pub extern "C" fn sparkwms_api_check(handle: *mut ApiHandle, err_out: *mut FfiEr
     })
 }
 
+/// Negotiate schema compatibility with the server and return the negotiated schema version, or
+/// `-1` on failure (including a version mismatch — check `err_out` for details). The app should
+/// warn the user to update rather than let commits start piling into a schema the server will
+/// reject.
+#[no_mangle]
+pub extern "C" fn sparkwms_api_schema_version(
+    handle: *mut ApiHandle,
+    err_out: *mut FfiError,
+) -> i32 {
+    if handle.is_null() {
+        write_error(
+            err_out,
+            AppError::Validation("handle pointer was null".into()),
+        );
+        return -1;
+    }
+
+    let handle_ref = unsafe { &*handle };
+
+    match handle_ref.runtime.block_on(handle_ref.api.negotiate()) {
+        Ok(version) => {
+            write_success(err_out);
+            version
+        }
+        Err(err) => {
+            write_error(err_out, err);
+            -1
+        }
+    }
+}
+
 /// Add a commit to the on-disk queue.
 #[no_mangle]
 pub extern "C" fn sparkwms_queue_enqueue(
@@ -268,14 +310,82 @@ pub extern "C" fn sparkwms_queue_len(path: *const c_char, err_out: *mut FfiError
     }
 }
 
-/// Start the commit manager loop on a background thread. Returns `false` if the thread
-/// could not be spawned or the inputs were invalid.
+/// Return the number of commits that exceeded their retry budget and were moved to the
+/// dead-letter queue.
+#[no_mangle]
+pub extern "C" fn sparkwms_queue_dead_len(path: *const c_char, err_out: *mut FfiError) -> i32 {
+    match (|| -> Result<usize, AppError> {
+        let path = path_from_ptr(path)?;
+        Ok(dead_queue_len(&path)?)
+    })() {
+        Ok(len) => {
+            write_success(err_out);
+            len as i32
+        }
+        Err(err) => {
+            write_error(err_out, err);
+            -1
+        }
+    }
+}
+
+/// Move every dead-lettered commit back onto the main queue for another attempt, resetting its
+/// retry state. Returns the number of commits requeued, or `-1` on failure.
+#[no_mangle]
+pub extern "C" fn sparkwms_queue_drain_dead_letter(
+    path: *const c_char,
+    err_out: *mut FfiError,
+) -> i32 {
+    match (|| -> Result<usize, AppError> {
+        let path = path_from_ptr(path)?;
+        Ok(drain_dead_letter_queue(&path)?)
+    })() {
+        Ok(count) => {
+            write_success(err_out);
+            count as i32
+        }
+        Err(err) => {
+            write_error(err_out, err);
+            -1
+        }
+    }
+}
+
+/// Register a callback to be invoked for every queue event (`EnqueuedItem`, `CommitSent`,
+/// `CommitFailed`, `QueueEmpty`, `ApiUnreachable`) instead of polling [`sparkwms_queue_len`].
+/// The callback is invoked from a dedicated dispatch thread with the event's numeric code and a
+/// JSON-encoded payload describing it.
+#[no_mangle]
+pub extern "C" fn sparkwms_register_event_callback(callback: EventCallback) {
+    events::register_callback(callback);
+}
+
+/// Owned handle to a running commit-manager background thread, returned by
+/// [`sparkwms_start_commit_manager`] and released via [`sparkwms_stop_commit_manager`].
+pub struct CommitManagerHandle {
+    join_handle: Option<thread::JoinHandle<()>>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Start the commit manager loop on a background thread.
+///
+/// `batch_size` caps how many ready commits are drained and sent per round-trip; values `<= 0`
+/// fall back to [`BatchConfig::default`]'s batch size. `concurrency` selects the upload mode:
+/// `<= 1` sends each batch as a single ordered statement (`UploadMode::Ordered`); values `> 1`
+/// split it into at most that many sub-batches and send them concurrently (`UploadMode::Parallel`).
+///
+/// Negotiates schema compatibility with the server before spawning the thread, so a mismatch is
+/// reported synchronously through `err_out` instead of only surfacing later as a `SchemaMismatch`
+/// event once the loop notices it on its own. Returns a null pointer if the thread could not be
+/// spawned, the inputs were invalid, or this negotiation failed.
 #[no_mangle]
 pub extern "C" fn sparkwms_start_commit_manager(
     connect_string: *const c_char,
     queue_path: *const c_char,
+    batch_size: i32,
+    concurrency: i32,
     err_out: *mut FfiError,
-) -> bool {
+) -> *mut CommitManagerHandle {
     let setup = (|| -> Result<(NeonAPI, PathBuf), AppError> {
         let connect = cstr_to_string(connect_string, "connect_string")?;
         let api = NeonAPI::new(&connect).map_err(AppError::from)?;
@@ -287,10 +397,46 @@ pub extern "C" fn sparkwms_start_commit_manager(
         Ok(tuple) => tuple,
         Err(err) => {
             write_error(err_out, err);
-            return false;
+            return ptr::null_mut();
         }
     };
 
+    let negotiate_runtime = match Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            write_error(
+                err_out,
+                AppError::Internal(format!("failed to create runtime: {err}")),
+            );
+            return ptr::null_mut();
+        }
+    };
+
+    if let Err(err) = negotiate_runtime.block_on(api.negotiate()) {
+        events::emit(events::Event::SchemaMismatch {
+            message: err.to_string(),
+        });
+        write_error(err_out, err);
+        return ptr::null_mut();
+    }
+
+    let batch = BatchConfig {
+        batch_size: if batch_size > 0 {
+            batch_size as usize
+        } else {
+            BatchConfig::default().batch_size
+        },
+        mode: if concurrency > 1 {
+            UploadMode::Parallel {
+                concurrency: concurrency as usize,
+            }
+        } else {
+            UploadMode::Ordered
+        },
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
     match thread::Builder::new()
         .name("sparkwms-commit-manager".into())
         .spawn(move || {
@@ -299,24 +445,57 @@ pub extern "C" fn sparkwms_start_commit_manager(
                 .build()
                 .expect("runtime");
 
-            if let Err(err) = runtime.block_on(commit_manager::commit_manager(api, path)) {
+            if let Err(err) = runtime.block_on(commit_manager::commit_manager_with_config(
+                api,
+                path,
+                RetryConfig::default(),
+                batch,
+                shutdown_rx,
+            )) {
                 eprintln!("commit manager loop exited: {err}");
             }
         }) {
-        Ok(_) => {
+        Ok(join_handle) => {
             write_success(err_out);
-            true
+            Box::into_raw(Box::new(CommitManagerHandle {
+                join_handle: Some(join_handle),
+                shutdown: shutdown_tx,
+            }))
         }
         Err(err) => {
             write_error(
                 err_out,
                 AppError::Internal(format!("failed to spawn commit manager: {err}")),
             );
-            false
+            ptr::null_mut()
         }
     }
 }
 
+/// Signal the commit-manager loop to stop, join its thread (bounded by
+/// [`SHUTDOWN_JOIN_TIMEOUT`]), and free the handle. Returns `true` if the thread stopped cleanly
+/// within the timeout. Safe to call with a null handle, which simply returns `false`.
+#[no_mangle]
+pub extern "C" fn sparkwms_stop_commit_manager(handle: *mut CommitManagerHandle) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+
+    let mut handle = unsafe { Box::from_raw(handle) };
+    let _ = handle.shutdown.send(true);
+
+    let Some(join_handle) = handle.join_handle.take() else {
+        return false;
+    };
+
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = done_tx.send(join_handle.join().is_ok());
+    });
+
+    done_rx.recv_timeout(SHUTDOWN_JOIN_TIMEOUT).unwrap_or(false)
+}
+
 /// Convenience helper for Dart/Flutter to dispose FFI owned strings.
 #[no_mangle]
 pub extern "C" fn sparkwms_string_from_rust(value: *const c_char) -> *mut c_char {
@@ -0,0 +1,114 @@
+//! Outbound event bus so the Flutter UI can react to queue progress instead of polling
+//! `sparkwms_queue_len`.
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// A C callback that receives an event code plus a JSON-encoded payload describing it.
+pub type EventCallback = extern "C" fn(event_code: i32, payload: *const c_char);
+
+/// Structured events emitted from the enqueue path and the commit-manager loop.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    EnqueuedItem { item_id: i16 },
+    CommitSent { item_id: i16 },
+    CommitFailed { item_id: i16, error: String },
+    QueueEmpty,
+    ApiUnreachable,
+    /// Schema negotiation with the server failed, either because its schema version doesn't
+    /// match this client's or because the version could not be read; the commit manager has
+    /// refused to start uploading against it.
+    SchemaMismatch { message: String },
+}
+
+impl Event {
+    /// Stable numeric code handed to the C callback alongside the JSON payload, so Dart doesn't
+    /// have to parse the JSON just to dispatch on event kind.
+    fn code(&self) -> i32 {
+        match self {
+            Event::EnqueuedItem { .. } => 0,
+            Event::CommitSent { .. } => 1,
+            Event::CommitFailed { .. } => 2,
+            Event::QueueEmpty => 3,
+            Event::ApiUnreachable => 4,
+            Event::SchemaMismatch { .. } => 5,
+        }
+    }
+}
+
+static EVENT_BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+
+fn event_bus() -> &'static broadcast::Sender<Event> {
+    EVENT_BUS.get_or_init(|| broadcast::channel(256).0)
+}
+
+/// Publish an event to every registered callback. A no-op if nothing is listening.
+#[allow(dead_code)]
+pub fn emit(event: Event) {
+    let _ = event_bus().send(event);
+}
+
+/// Register a C callback to receive every future event. Events are dispatched from a dedicated
+/// background thread so the caller's enqueue/commit-manager path never blocks on a slow callback.
+#[allow(dead_code)]
+pub fn register_callback(callback: EventCallback) {
+    let mut rx = event_bus().subscribe();
+
+    std::thread::Builder::new()
+        .name("sparkwms-event-dispatch".into())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("event dispatch runtime");
+
+            runtime.block_on(async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(event) => {
+                            let payload = serde_json::to_string(&event).unwrap_or_default();
+                            if let Ok(cstr) = CString::new(payload) {
+                                callback(event.code(), cstr.as_ptr());
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+        })
+        .expect("failed to spawn event dispatch thread");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_codes_are_stable() {
+        assert_eq!(Event::EnqueuedItem { item_id: 1 }.code(), 0);
+        assert_eq!(Event::CommitSent { item_id: 1 }.code(), 1);
+        assert_eq!(
+            Event::CommitFailed {
+                item_id: 1,
+                error: "x".into()
+            }
+            .code(),
+            2
+        );
+        assert_eq!(Event::QueueEmpty.code(), 3);
+        assert_eq!(Event::ApiUnreachable.code(), 4);
+        assert_eq!(
+            Event::SchemaMismatch {
+                message: "x".into()
+            }
+            .code(),
+            5
+        );
+    }
+}
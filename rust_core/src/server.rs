@@ -6,7 +6,14 @@ use csv::Writer;
 use neon_wasi_http::{Client, QueryBuilder};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::errors::AppError;
+
+/// Schema version this client expects the server's `commits`/`overview`/`locations` tables to
+/// match. Bump this whenever one of those tables changes in a way old clients can't handle, and
+/// update the server's `server_metadata.schema_version` in lockstep.
+pub const SCHEMA_VERSION: i32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Commit {
     pub device_id: String,
     pub location: String,
@@ -39,6 +46,11 @@ struct ItemsRow {
     name: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct SchemaVersionRow {
+    schema_version: i32,
+}
+
 #[allow(dead_code)]
 impl NeonAPI {
     pub fn new(connect_string: impl Into<String>) -> Result<Self> {
@@ -64,6 +76,36 @@ impl NeonAPI {
         .await
     }
 
+    /// Insert several commits in a single multi-row statement instead of one round-trip per
+    /// commit. Useful when a device reconnects with a large backlog queued up.
+    pub async fn send_commits(&self, commits: &[Commit]) -> Result<()> {
+        if commits.is_empty() {
+            return Ok(());
+        }
+
+        let values_clause = (0..commits.len())
+            .map(|i| {
+                let base = i * 4;
+                format!("(${}, ${}, ${}, ${})", base + 1, base + 2, base + 3, base + 4)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let query =
+            format!("INSERT INTO commits (device_id, location, delta, item_id) VALUES {values_clause}");
+
+        let mut builder = QueryBuilder::new(&query);
+        for commit in commits {
+            builder = builder
+                .bind(&commit.device_id)
+                .bind(&commit.location)
+                .bind(commit.delta)
+                .bind(commit.item_id);
+        }
+
+        builder.execute(&self.client).await
+    }
+
     pub async fn export_overview_to_csv(&self, path: &String) -> Result<()> {
         let rows: Vec<OverviewRow> = QueryBuilder::new("SELECT * FROM overview")
             .fetch_all(&self.client)
@@ -95,7 +137,7 @@ impl NeonAPI {
     }
 
     pub async fn export_items_to_csv(&self, path: &String) -> Result<()> {
-        let rows: Vec<ItemsRow> = QueryBuilder::new("SELECT * FROM locations")
+        let rows: Vec<ItemsRow> = QueryBuilder::new("SELECT * FROM items")
             .fetch_all(&self.client)
             .await?;
 
@@ -115,4 +157,72 @@ impl NeonAPI {
             .await
             .is_ok()
     }
+
+    /// Negotiate schema compatibility with the server: read its reported `schema_version` from
+    /// `server_metadata` and compare it against [`SCHEMA_VERSION`]. Returns the negotiated
+    /// version on a match, or a distinct `AppError::Server` describing the mismatch so callers
+    /// can warn the user before commits start piling into a schema the server will reject.
+    pub async fn negotiate(&self) -> std::result::Result<i32, AppError> {
+        let rows: Vec<SchemaVersionRow> =
+            QueryBuilder::new("SELECT schema_version FROM server_metadata LIMIT 1")
+                .fetch_all(&self.client)
+                .await
+                .map_err(AppError::from)?;
+
+        let server_version = rows.first().map(|row| row.schema_version).ok_or_else(|| {
+            AppError::Server {
+                status: 0,
+                message: "server_metadata table returned no schema_version row".into(),
+            }
+        })?;
+
+        check_schema_version(server_version)
+    }
+}
+
+/// The version-compare half of [`NeonAPI::negotiate`], pulled out as a free function so the
+/// match/too-old/too-new branches can be unit-tested without a live Postgres connection -- the
+/// same reasoning `commit_manager`'s `CommitSender` trait extraction used for `send_batch`.
+fn check_schema_version(server_version: i32) -> std::result::Result<i32, AppError> {
+    if server_version < SCHEMA_VERSION {
+        return Err(AppError::Server {
+            status: 426,
+            message: format!(
+                "server schema version {server_version} is older than client version {SCHEMA_VERSION}; the server needs to be upgraded"
+            ),
+        });
+    }
+
+    if server_version > SCHEMA_VERSION {
+        return Err(AppError::Server {
+            status: 426,
+            message: format!(
+                "server schema version {server_version} is newer than client version {SCHEMA_VERSION}; the app needs to be upgraded"
+            ),
+        });
+    }
+
+    Ok(server_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_version_negotiates_successfully() {
+        assert_eq!(check_schema_version(SCHEMA_VERSION).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn older_server_version_is_rejected() {
+        let err = check_schema_version(SCHEMA_VERSION - 1).unwrap_err();
+        assert!(matches!(err, AppError::Server { status: 426, .. }));
+    }
+
+    #[test]
+    fn newer_server_version_is_rejected() {
+        let err = check_schema_version(SCHEMA_VERSION + 1).unwrap_err();
+        assert!(matches!(err, AppError::Server { status: 426, .. }));
+    }
 }